@@ -0,0 +1,186 @@
+use crate::errors::*;
+use crate::engine::isolation;
+use crate::geoip::{GeoIP, AsnDB};
+
+use std::io::Read;
+use std::net::{TcpListener, TcpStream};
+
+
+/// The most bytes `read_token_line` will read of a client's handshake line (the line that must
+/// match `--token` before an agent will read a `StartCommand`) before giving up on the connection.
+const TOKEN_LINE_LIMIT: usize = 4096;
+
+/// Runs `sn0int agent`: listens for incoming connections from `sn0int run --agent host:port`
+/// and drives each one with `run_worker_over` instead of stdio, so module execution (and its
+/// network egress) can be offloaded onto this host. `geoip`/`asn`/`psl` are loaded once up
+/// front and handed to each connection's forked child, same data every spawn would otherwise
+/// have to reload from disk.
+///
+/// There is no TLS here, so a connection is only as trustworthy as the network it crosses:
+/// `bind` is refused unless it's a loopback address (pass `allow_remote` to override, eg to run
+/// over a VPN/tailnet between scan hosts you already trust), and every connection must open with
+/// a line matching `token` before it's allowed to hand over a module to run.
+pub fn run(bind: &str, token: &str, allow_remote: bool, geoip: GeoIP, asn: AsnDB, psl: String, no_sandbox: bool) -> Result<()> {
+    if !allow_remote {
+        let addr = bind.parse::<std::net::SocketAddr>()
+            .with_context(|_| format!("Invalid bind address {:?}", bind))?;
+        if !addr.ip().is_loopback() {
+            bail!("Refusing to bind agent socket on non-loopback address {} without --allow-remote \
+                   (the connection is plaintext, protected only by --token)", addr);
+        }
+    }
+
+    #[cfg(unix)]
+    unsafe {
+        // Let the kernel reap our forked children instead of us having to waitpid() them;
+        // nothing here ever cares about a connection child's exit status.
+        libc::signal(libc::SIGCHLD, libc::SIG_IGN);
+    }
+
+    let listener = TcpListener::bind(bind)
+        .with_context(|_| format!("Failed to bind agent socket on {:?}", bind))?;
+    info!("Agent listening on {}", bind);
+
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(err) => {
+                warn!("Failed to accept agent connection: {}", err);
+                continue;
+            },
+        };
+
+        let geoip = geoip.clone();
+        let asn = asn.clone();
+        let psl = psl.clone();
+        let token = token.to_string();
+        dispatch_connection(stream, token, geoip, asn, psl, no_sandbox);
+    }
+
+    Ok(())
+}
+
+/// Hands one connection off to a fresh process so that a crash, seccomp kill, or memory
+/// corruption in an untrusted module only ever takes down that one connection - and so the
+/// `unshare(CLONE_NEWUSER)` in `sandbox::enforce` has a single-threaded process to act on, which
+/// `libc::unshare` requires and a thread of this (inevitably multi-threaded, once it's accepted
+/// more than one connection) daemon could never guarantee. `fork()` rather than `fork()`+exec:
+/// the child needs the already-loaded `geoip`/`asn`/`psl` (multi-hundred-MB databases we don't
+/// want to reload from disk per connection) and the connected socket, both of which it inherits
+/// for free as part of the copied process image; nothing past this point needs to be shared
+/// back with the parent, so there's no exec and no IPC to wire up.
+#[cfg(unix)]
+fn dispatch_connection(stream: TcpStream, token: String, geoip: GeoIP, asn: AsnDB, psl: String, no_sandbox: bool) {
+    match unsafe { libc::fork() } {
+        -1 => error!("Failed to fork agent connection handler: {}", std::io::Error::last_os_error()),
+        0 => {
+            // Child: our own address space and (after sandbox::enforce) our own namespaces.
+            // Never return into the accept loop - always exit, on every path out of here.
+            let code = match handle_connection(stream, &token, geoip, asn, psl, no_sandbox) {
+                Ok(()) => 0,
+                Err(err) => {
+                    error!("Agent connection failed: {}", err);
+                    1
+                },
+            };
+            std::process::exit(code);
+        },
+        _pid => {
+            // Parent: this is the child's copy of the socket now, not ours.
+            drop(stream);
+        },
+    }
+}
+
+/// No `fork(2)` outside unix, so fall back to a thread; it still shares this process's address
+/// space and namespace state, but there's nothing more isolated available on these targets.
+#[cfg(not(unix))]
+fn dispatch_connection(stream: TcpStream, token: String, geoip: GeoIP, asn: AsnDB, psl: String, no_sandbox: bool) {
+    warn!("Agent connections are not process-isolated on this platform");
+    std::thread::spawn(move || {
+        if let Err(err) = handle_connection(stream, &token, geoip, asn, psl, no_sandbox) {
+            error!("Agent connection failed: {}", err);
+        }
+    });
+}
+
+fn handle_connection(mut stream: TcpStream, token: &str, geoip: GeoIP, asn: AsnDB, psl: String, no_sandbox: bool) -> Result<()> {
+    let line = read_token_line(&mut stream)
+        .context("Failed to read agent handshake")?;
+
+    if line != token {
+        bail!("Rejected agent connection: bad token");
+    }
+
+    isolation::run_worker_over(stream, geoip, asn, psl, no_sandbox)
+}
+
+/// Reads a single `\n`-terminated line directly off `stream`, one byte at a time, so nothing
+/// past the handshake gets buffered and lost before the jsonrpc `Reporter` takes over the stream.
+/// Generic over `Read` purely so the handshake parsing can be exercised without a real socket.
+fn read_token_line<R: Read>(stream: &mut R) -> Result<String> {
+    let mut line = Vec::new();
+    let mut byte = [0u8; 1];
+
+    loop {
+        if line.len() >= TOKEN_LINE_LIMIT {
+            bail!("Agent handshake line exceeded {} bytes", TOKEN_LINE_LIMIT);
+        }
+
+        let n = stream.read(&mut byte)?;
+        if n == 0 {
+            bail!("Connection closed before handshake completed");
+        }
+        if byte[0] == b'\n' {
+            break;
+        }
+        line.push(byte[0]);
+    }
+
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+
+    String::from_utf8(line).context("Agent handshake line was not valid UTF-8")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn read_token_line_strips_trailing_newline() {
+        let mut input = Cursor::new(b"secret-token\nrest-of-stream".to_vec());
+        assert_eq!(read_token_line(&mut input).unwrap(), "secret-token");
+    }
+
+    #[test]
+    fn read_token_line_strips_trailing_crlf() {
+        let mut input = Cursor::new(b"secret-token\r\nrest-of-stream".to_vec());
+        assert_eq!(read_token_line(&mut input).unwrap(), "secret-token");
+    }
+
+    #[test]
+    fn read_token_line_does_not_consume_past_the_newline() {
+        let mut input = Cursor::new(b"secret-token\n{\"jsonrpc\":\"2.0\"}\n".to_vec());
+        read_token_line(&mut input).unwrap();
+
+        let mut rest = Vec::new();
+        input.read_to_end(&mut rest).unwrap();
+        assert_eq!(rest, b"{\"jsonrpc\":\"2.0\"}\n");
+    }
+
+    #[test]
+    fn read_token_line_rejects_oversized_line() {
+        let input = vec![b'a'; TOKEN_LINE_LIMIT + 1];
+        let mut input = Cursor::new(input);
+        assert!(read_token_line(&mut input).is_err());
+    }
+
+    #[test]
+    fn read_token_line_rejects_eof_without_newline() {
+        let mut input = Cursor::new(b"no-newline-here".to_vec());
+        assert!(read_token_line(&mut input).is_err());
+    }
+}
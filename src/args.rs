@@ -47,6 +47,12 @@ pub enum SubCommand {
     #[structopt(author="", name="completions")]
     /// Generate shell completions
     Completions(Completions),
+    #[structopt(author="", name="agent")]
+    /// Run a remote worker agent that `run --agent` can offload modules to
+    Agent(Agent),
+    #[structopt(author="", name="verify")]
+    /// Recompute installed modules' hashes and check them against sn0int.lock
+    Verify(Verify),
 }
 
 #[derive(Debug, StructOpt)]
@@ -58,12 +64,25 @@ pub struct Run {
     pub threads: usize,
     #[structopt(short="v", long="verbose", parse(from_occurrences))]
     pub verbose: u64,
+    /// Run the module without the seccomp/namespace sandbox. Only use this for modules you trust.
+    #[structopt(long="no-sandbox")]
+    pub no_sandbox: bool,
+    /// Run the module on a remote `sn0int agent` instead of spawning a local worker
+    #[structopt(long="agent")]
+    pub agent: Option<String>,
+    /// Shared secret the agent at --agent expects before it will accept a start command.
+    /// Defaults to $SN0INT_AGENT_TOKEN.
+    #[structopt(long="agent-token", env="SN0INT_AGENT_TOKEN")]
+    pub agent_token: Option<String>,
 }
 
 #[derive(Debug, StructOpt)]
 pub struct Sandbox {
     /// This value is only used for process listings
     label: String,
+    /// Skip setting up the seccomp/namespace sandbox for this worker. Only use this for modules you trust.
+    #[structopt(long="no-sandbox")]
+    pub no_sandbox: bool,
 }
 
 #[derive(Debug, StructOpt)]
@@ -82,7 +101,11 @@ pub struct Install {
     /// The script to install
     pub module: ModuleID,
     /// Specify the version, defaults to the latest version
+    #[structopt(conflicts_with="locked")]
     pub version: Option<String>,
+    /// Install exactly the version and sha256 pinned in sn0int.lock instead of the latest
+    #[structopt(long="locked")]
+    pub locked: bool,
 }
 
 #[derive(Debug, StructOpt)]
@@ -96,3 +119,28 @@ pub struct Completions {
     #[structopt(raw(possible_values="&Shell::variants()"))]
     pub shell: Shell,
 }
+
+#[derive(Debug, StructOpt)]
+pub struct Verify {
+    /// Only verify this module instead of everything in sn0int.lock
+    pub module: Option<ModuleID>,
+}
+
+#[derive(Debug, StructOpt)]
+pub struct Agent {
+    /// The address to bind the agent socket on. Refused unless it's a loopback address, since
+    /// connections are plaintext and only protected by --token; pass --allow-remote to override.
+    #[structopt(short="b", long="bind", default_value="127.0.0.1:6613")]
+    pub bind: String,
+    /// Shared secret a `run --agent` client must present before this agent will accept a start
+    /// command. Defaults to $SN0INT_AGENT_TOKEN.
+    #[structopt(long="token", env="SN0INT_AGENT_TOKEN")]
+    pub token: String,
+    /// Allow binding to a non-loopback address. The connection is still plaintext, so only do
+    /// this over a network you already trust (eg a VPN/tailnet between scan hosts).
+    #[structopt(long="allow-remote")]
+    pub allow_remote: bool,
+    /// Run workers without the seccomp/namespace sandbox. Only use this for modules you trust.
+    #[structopt(long="no-sandbox")]
+    pub no_sandbox: bool,
+}
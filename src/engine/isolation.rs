@@ -3,14 +3,20 @@ use chrootable_https::dns::Resolver;
 use crate::engine::{Environment, Module, Reporter};
 use crate::geoip::{GeoIP, AsnDB};
 use crate::psl::Psl;
-use serde_json;
-use crate::worker::{Event, Event2, LogEvent, ExitEvent, EventSender, EventWithCallback};
+use crate::sandbox::{self, SandboxPolicy};
+use crate::transport::{Transport, LocalTransport, TcpTransport};
+use serde_json::{self, Value};
+use crate::worker::{Envelope, EnvelopeBody, Event, Event2, LogEvent, ExitEvent, WorkerError, EventSender, EventWithCallback, RequestIdGen, StdioEvent};
 
-use std::env;
+use crate::lockfile;
+
+use std::collections::HashMap;
 use std::io::prelude::*;
 use std::io::{self, BufReader, BufRead, stdin, Stdin, Stdout};
+use std::net::TcpStream;
+use std::path::Path;
 use std::sync::{mpsc, Arc, Mutex};
-use std::process::{Command, Child, Stdio, ChildStdin, ChildStdout};
+use std::thread;
 
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -19,47 +25,56 @@ pub struct StartCommand {
     dns_config: Resolver,
     module: Module,
     arg: serde_json::Value,
+    /// The sha256 `sn0int.lock` recorded for this module, resolved here on the sender's own
+    /// workspace before the module ever goes out. Deliberately a digest and not a workspace
+    /// path: the worker side of this may be a `sn0int agent` on a different host entirely, with
+    /// no access to (or knowledge of) the sender's `sn0int.lock` or its filesystem layout, so the
+    /// only thing that can be checked on that side is "does the module I was actually handed
+    /// match the digest the sender locked" - not "is there a file matching some local path".
+    expected_sha256: String,
 }
 
 impl StartCommand {
-    pub fn new(verbose: u64, dns_config: Resolver, module: Module, arg: serde_json::Value) -> StartCommand {
+    pub fn new(verbose: u64, dns_config: Resolver, module: Module, arg: serde_json::Value, expected_sha256: String) -> StartCommand {
         StartCommand {
             verbose,
             dns_config,
             module,
-            arg
+            arg,
+            expected_sha256,
         }
     }
 }
 
+/// Requests the child has sent us that are still waiting on a reply, keyed by the id the
+/// child picked when it made the call. A thread is parked on each pending reply's channel so
+/// the recv loop never has to block waiting for one specific call to finish.
+type PendingReplies = Arc<Mutex<HashMap<u64, ()>>>;
+
 pub struct Supervisor {
-    child: Child,
-    stdin: ChildStdin,
-    stdout: BufReader<ChildStdout>,
+    transport: Box<Transport>,
+    pending: PendingReplies,
 }
 
 impl Supervisor {
+    /// Spawn a worker as a local child process, sandboxed by `sn0int sandbox`.
     pub fn setup(module: &Module) -> Result<Supervisor> {
-        let exe = env::current_exe()
-            .context("Failed to find current executable")?;
-
-        let mut child = Command::new(exe)
-            .arg("sandbox")
-            .arg(&module.canonical())
-            .stdin(Stdio::piped())
-            .stdout(Stdio::piped())
-            .spawn()
-            .context("Failed to spawn child process")?;
+        let transport = LocalTransport::spawn(module)?;
+        Ok(Supervisor::with_transport(Box::new(transport)))
+    }
 
-        let stdin = child.stdin.take().expect("Failed to take child stdin");
-        let stdout = child.stdout.take().expect("Failed to take child stdout");
-        let stdout = BufReader::new(stdout);
+    /// Drive a worker on a remote `sn0int agent` instead of spawning one locally. `token` must
+    /// match the agent's `--token`, or it will close the connection before reading a start command.
+    pub fn connect(addr: &str, token: &str) -> Result<Supervisor> {
+        let transport = TcpTransport::connect(addr, token)?;
+        Ok(Supervisor::with_transport(Box::new(transport)))
+    }
 
-        Ok(Supervisor {
-            child,
-            stdin,
-            stdout,
-        })
+    fn with_transport(transport: Box<Transport>) -> Supervisor {
+        Supervisor {
+            transport,
+            pending: Arc::new(Mutex::new(HashMap::new())),
+        }
     }
 
     pub fn send_start(&mut self, start: &StartCommand) -> Result<()> {
@@ -68,96 +83,252 @@ impl Supervisor {
         Ok(())
     }
 
-    pub fn send(&mut self, value: &serde_json::Value) -> Result<()> {
+    pub fn send(&mut self, value: &Value) -> Result<()> {
         let mut value = serde_json::to_string(value)?;
         value.push('\n');
-        self.stdin.write_all(value.as_bytes())?;
+        self.transport.writer().lock().unwrap().write_all(value.as_bytes())?;
         debug!("Supervisor sent: {:?}", value);
         Ok(())
     }
 
-    pub fn send_struct<T: serde::Serialize>(&mut self, value: T, tx: &EventSender) {
-        let value = serde_json::to_value(value).expect("Failed to serialize reply");
-        if let Err(_) = self.send(&value) {
-            tx.send(Event2::Log(LogEvent::Error("Failed to send to child".into())));
-        }
+    fn send_envelope(writer: &Arc<Mutex<Box<Write + Send>>>, envelope: &Envelope) -> Result<()> {
+        let mut line = serde_json::to_string(envelope)?;
+        line.push('\n');
+        writer.lock().unwrap().write_all(line.as_bytes())?;
+        debug!("Supervisor sent: {:?}", line);
+        Ok(())
     }
 
-    pub fn recv(&mut self) -> Result<Event> {
+    /// Read the next line the child sends us and decode it as a jsonrpc envelope.
+    fn recv_envelope(&mut self) -> Result<Envelope> {
         let mut line = String::new();
-        let len = self.stdout.read_line(&mut line)?;
+        let len = self.transport.reader().read_line(&mut line)?;
 
-        let event = serde_json::from_str(&line[..len])?;
-        debug!("Supervisor received: {:?}", event);
-        Ok(event)
+        let envelope = serde_json::from_str(&line[..len])?;
+        debug!("Supervisor received: {:?}", envelope);
+        Ok(envelope)
     }
 
-    pub fn wait(&mut self) -> Result<()> {
-        let exit = self.child.wait()
-            .context("Failed to wait for child")?;
-
-        if exit.success() {
-            Ok(())
-        } else {
-            bail!("Child signaled error")
+    /// Read the next call the child makes and turn it into an `Event`. A call that carries an
+    /// id comes back with that id attached, so the caller can answer it later.
+    pub fn recv(&mut self) -> Result<Event> {
+        loop {
+            let envelope = self.recv_envelope()?;
+
+            let (method, params) = match envelope.body {
+                EnvelopeBody::Call { method, params } => (method, params),
+                EnvelopeBody::Result { .. } | EnvelopeBody::Error { .. } => {
+                    bail!("Received an unsolicited reply from child");
+                },
+            };
+
+            let event = match (method.as_str(), envelope.id) {
+                ("log", None) => Event::Log(serde_json::from_value(params)?),
+                ("exit", None) => Event::Exit(serde_json::from_value(params)?),
+                ("stdio", Some(id)) => Event::Stdio(serde_json::from_value(params)?, id),
+                ("database", Some(id)) => Event::Database(params, id),
+                (method, None) => bail!("Received unknown notification from child: {:?}", method),
+                (method, Some(_)) => bail!("Received unknown request from child: {:?}", method),
+            };
+
+            return Ok(event);
         }
     }
 
-    pub fn send_event_callback<T: EventWithCallback>(&mut self, event: T, tx: &EventSender)
-        where <T as EventWithCallback>::Payload: serde::Serialize
+    pub fn wait(&mut self) -> Result<()> {
+        self.transport.wait()
+    }
+
+    /// Hand a request off to the host (via `tx`) and, once it answers, write the jsonrpc
+    /// reply back to the child on whatever thread the answer arrives on. This never blocks
+    /// the caller, so several of these can be in flight for the same child at once.
+    pub fn send_event_callback<T: EventWithCallback>(&mut self, id: u64, event: T, tx: &EventSender)
+        where <T as EventWithCallback>::Payload: serde::Serialize + Send + 'static
     {
+        {
+            let mut pending = self.pending.lock().unwrap();
+            if pending.insert(id, ()).is_some() {
+                // Ids only need to be unique per supervisor instance; a collision means the
+                // child violated the protocol, so we drop the older registration rather than panic.
+                warn!("Duplicate request id from child: {}", id);
+            }
+        }
+
         let (tx2, rx2) = mpsc::channel();
         tx.send(event.with_callback(tx2));
-        let reply = rx2.recv().unwrap();
 
-        self.send_struct(reply, tx);
+        let writer = self.transport.writer();
+        let pending = self.pending.clone();
+        let tx = tx.clone();
+        thread::spawn(move || {
+            let reply = rx2.recv().unwrap();
+            pending.lock().unwrap().remove(&id);
+
+            let envelope = match serde_json::to_value(reply) {
+                Ok(result) => Envelope::result(id, result),
+                Err(err) => Envelope::error(id, err.to_string()),
+            };
+
+            if Self::send_envelope(&writer, &envelope).is_err() {
+                tx.send(Event2::Log(LogEvent::Error("Failed to send to child".into())));
+            }
+        });
+    }
+
+    /// Answer a request that doesn't need to round-trip through the host, such as a stdin
+    /// read the supervisor can satisfy locally.
+    pub fn reply(&mut self, id: u64, result: Value) -> Result<()> {
+        let envelope = Envelope::result(id, result);
+        Self::send_envelope(&self.transport.writer(), &envelope)
     }
 }
 
+/// Everything a `Reporter` needs to speak jsonrpc over a pair of blocking read/write streams.
+/// `StdioReporter` and `TcpReporter` are the same few lines wired to different streams, so the
+/// framing itself lives here once.
 #[derive(Debug)]
-pub struct StdioReporter {
-    stdin: Stdin,
-    stdout: Stdout,
+struct RpcStream<R, W> {
+    reader: BufReader<R>,
+    writer: W,
+    ids: RequestIdGen,
 }
 
-impl StdioReporter {
-    pub fn setup() -> StdioReporter {
-        let stdin = io::stdin();
-        let stdout = io::stdout();
+impl<R: Read, W: Write> RpcStream<R, W> {
+    fn new(reader: R, writer: W) -> RpcStream<R, W> {
+        RpcStream {
+            reader: BufReader::new(reader),
+            writer,
+            ids: RequestIdGen::new(),
+        }
+    }
+
+    fn recv_start(&mut self) -> Result<StartCommand> {
+        let mut line = String::new();
+        let len = self.reader.read_line(&mut line)?;
+        let start = serde_json::from_str(&line[..len])?;
+        Ok(start)
+    }
 
-        StdioReporter {
-            stdin,
-            stdout,
+    /// Send a request and block until the reply carrying the same id comes back. The module
+    /// side only ever has one call in flight at a time, so there is no need for the
+    /// pending-request bookkeeping `Supervisor` does on the other end of the pipe.
+    fn call(&mut self, method: &str, params: Value) -> Result<Value> {
+        let id = self.ids.next();
+        self.send_envelope(&Envelope::request(id, method, params))?;
+
+        loop {
+            let envelope = self.recv_envelope()?;
+            match envelope.id {
+                Some(reply_id) if reply_id == id => return envelope.into_reply_value(),
+                _ => continue,
+            }
         }
     }
 
+    fn send_envelope(&mut self, envelope: &Envelope) -> Result<()> {
+        let mut line = serde_json::to_string(envelope)?;
+        line.push('\n');
+        self.writer.write_all(line.as_bytes())?;
+        debug!("Reporter sent: {:?}", line);
+        Ok(())
+    }
+
+    fn recv_envelope(&mut self) -> Result<Envelope> {
+        let mut line = String::new();
+        let len = self.reader.read_line(&mut line)?;
+
+        let envelope = serde_json::from_str(&line[..len])?;
+        debug!("Reporter received: {:?}", envelope);
+        Ok(envelope)
+    }
+
+    fn send_event(&mut self, event: &Event) -> Result<()> {
+        let envelope = match event {
+            Event::Log(log) => Envelope::notification("log", serde_json::to_value(log)?),
+            Event::Exit(exit) => Envelope::notification("exit", serde_json::to_value(exit)?),
+            Event::Database(params, id) => Envelope::request(*id, "database", params.clone()),
+            Event::Stdio(event, id) => Envelope::request(*id, "stdio", serde_json::to_value(event)?),
+        };
+        self.send_envelope(&envelope)
+    }
+}
+
+#[derive(Debug)]
+pub struct StdioReporter(RpcStream<Stdin, Stdout>);
+
+impl StdioReporter {
+    pub fn setup() -> StdioReporter {
+        StdioReporter(RpcStream::new(io::stdin(), io::stdout()))
+    }
+
     pub fn recv_start(&mut self) -> Result<StartCommand> {
-        let value = self.recv()?;
-        let event = serde_json::from_value(value)?;
-        Ok(event)
+        self.0.recv_start()
+    }
+
+    pub fn call(&mut self, method: &str, params: Value) -> Result<Value> {
+        self.0.call(method, params)
     }
 }
 
 impl Reporter for StdioReporter {
     fn send(&mut self, event: &Event) -> Result<()> {
-        let mut event = serde_json::to_string(&event)?;
-        event.push('\n');
-        self.stdout.write_all(event.as_bytes())?;
-        debug!("Reporter sent: {:?}", event);
-        Ok(())
+        self.0.send_event(event)
     }
 
-    fn recv(&mut self) -> Result<serde_json::Value> {
-        let mut line = String::new();
-        let len = self.stdin.read_line(&mut line)?;
+    fn recv(&mut self) -> Result<Value> {
+        let envelope = self.0.recv_envelope()?;
+        envelope.into_reply_value()
+    }
+}
+
+/// Drives a worker over a TCP connection instead of stdio, for `sn0int agent`.
+#[derive(Debug)]
+pub struct TcpReporter(RpcStream<TcpStream, TcpStream>);
+
+impl TcpReporter {
+    pub fn setup(stream: TcpStream) -> Result<TcpReporter> {
+        let writer = stream.try_clone()
+            .context("Failed to clone agent connection")?;
+        Ok(TcpReporter(RpcStream::new(stream, writer)))
+    }
+
+    pub fn recv_start(&mut self) -> Result<StartCommand> {
+        self.0.recv_start()
+    }
+
+    pub fn call(&mut self, method: &str, params: Value) -> Result<Value> {
+        self.0.call(method, params)
+    }
+}
+
+impl Reporter for TcpReporter {
+    fn send(&mut self, event: &Event) -> Result<()> {
+        self.0.send_event(event)
+    }
+
+    fn recv(&mut self) -> Result<Value> {
+        let envelope = self.0.recv_envelope()?;
+        envelope.into_reply_value()
+    }
+}
 
-        let event = serde_json::from_str(&line[..len])?;
-        debug!("Reporter received: {:?}", event);
-        Ok(event)
+fn handle_stdio(supervisor: &mut Supervisor, id: u64, event: StdioEvent, reader: &mut Option<BufReader<Stdin>>) -> Result<()> {
+    match event {
+        StdioEvent::ReadLine => {
+            let mut line = String::new();
+            if let Some(reader) = reader {
+                reader.read_line(&mut line)?;
+            }
+            supervisor.reply(id, Value::from(line))
+        },
     }
 }
 
-pub fn spawn_module(module: Module, tx: &EventSender, arg: serde_json::Value, verbose: u64, has_stdin: bool) -> Result<()> {
+/// Drives one module end to end and returns how it exited. The scheduler that owns `tx` can
+/// act on the returned `ExitEvent`'s `ErrorClass` directly, or on the identical `Event2::Exit`
+/// pushed through `tx` as the loop below breaks - whichever is more convenient for the thread
+/// that's actually watching this particular module.
+pub fn spawn_module(module: Module, tx: &EventSender, arg: serde_json::Value, verbose: u64, has_stdin: bool, agent: Option<(&str, &str)>, workspace: &Path) -> Result<ExitEvent> {
     let dns_config = Resolver::from_system()?;
 
     let mut reader = if has_stdin {
@@ -166,31 +337,66 @@ pub fn spawn_module(module: Module, tx: &EventSender, arg: serde_json::Value, ve
         None
     };
 
-    let mut supervisor = Supervisor::setup(&module)?;
-    supervisor.send_start(&StartCommand::new(verbose, dns_config, module, arg))?;
+    // Resolved here, on whichever machine actually has `sn0int.lock`, rather than asking the
+    // worker (possibly a remote `sn0int agent`) to go look one up itself - see `StartCommand`.
+    let module_id = module.canonical().parse()
+        .context("Module has no valid id to verify against sn0int.lock")?;
+    let expected_sha256 = lockfile::expected_sha256(workspace, &module_id)?;
 
-    loop {
+    let mut supervisor = match agent {
+        Some((addr, token)) => Supervisor::connect(addr, token)?,
+        None => Supervisor::setup(&module)?,
+    };
+    supervisor.send_start(&StartCommand::new(verbose, dns_config, module, arg, expected_sha256))?;
+
+    let exit = loop {
         match supervisor.recv()? {
             Event::Log(event) => tx.send(Event2::Log(event)),
-            Event::Database(object) => supervisor.send_event_callback(object, &tx),
-            Event::Stdio(object) => object.apply(&mut supervisor, tx, &mut reader),
+            Event::Database(object, id) => supervisor.send_event_callback(id, object, &tx),
+            Event::Stdio(event, id) => handle_stdio(&mut supervisor, id, event, &mut reader)?,
             Event::Exit(event) => {
-                if let ExitEvent::Err(err) = event {
-                    tx.send(Event2::Log(LogEvent::Error(err)));
+                if let ExitEvent::Err(ref err) = event {
+                    tx.send(Event2::Log(LogEvent::Error(err.message.clone())));
                 }
-                break;
+                break event;
             },
         }
-    }
+    };
 
+    tx.send(Event2::Exit(exit.clone()));
     supervisor.wait()?;
 
-    Ok(())
+    Ok(exit)
 }
 
-pub fn run_worker(geoip: GeoIP, asn: AsnDB, psl: String) -> Result<()> {
-    let mut reporter = StdioReporter::setup();
-    let start = reporter.recv_start()?;
+/// Deliver a terminal `WorkerError` to the host before giving up, so a failure that happens
+/// before `start.module.run(...)` is ever called (a failed sandbox setup, a lockfile
+/// mismatch) still reaches `Event2::Exit` with its class intact, the same as a
+/// failure the module raises while running.
+fn fail_exit<T: Reporter>(reporter: &mut T, err: WorkerError) -> Result<()> {
+    let message = err.message.clone();
+    reporter.send(&Event::Exit(ExitEvent::Err(err)))?;
+    bail!("{}", message)
+}
+
+fn run_worker_with<T: Reporter + 'static>(mut reporter: T, start: StartCommand, geoip: GeoIP, asn: AsnDB, psl: String, no_sandbox: bool) -> Result<()> {
+    // Checked against the bytes actually embedded in `start.module`, not re-read from disk: on
+    // a remote `sn0int agent` there is no shared filesystem to re-read from, and `workspace`
+    // would just be a path from the sender's machine - see `StartCommand::expected_sha256`.
+    if let Err(err) = lockfile::verify_bytes(&start.expected_sha256, start.module.source().as_bytes()) {
+        return fail_exit(&mut reporter, WorkerError::sandbox(
+            format!("Module failed sn0int.lock verification: {}", err)));
+    }
+
+    if no_sandbox {
+        warn!("Sandbox disabled with --no-sandbox, module is running unconfined");
+    } else {
+        let policy = SandboxPolicy::for_module(&start.module);
+        if let Err(err) = sandbox::enforce(policy) {
+            return fail_exit(&mut reporter, WorkerError::sandbox(
+                format!("Failed to set up sandbox: {}", err)));
+        }
+    }
 
     let psl = Psl::from_str(&psl)
         .context("Failed to load public suffix list")?;
@@ -212,9 +418,23 @@ pub fn run_worker(geoip: GeoIP, asn: AsnDB, psl: String) -> Result<()> {
 
     let event = match result {
         Ok(_) => ExitEvent::Ok,
-        Err(err) => ExitEvent::Err(err.to_string()),
+        Err(err) => ExitEvent::Err(WorkerError::from(&err)),
     };
     reporter.send(&Event::Exit(event))?;
 
     Ok(())
 }
+
+pub fn run_worker(geoip: GeoIP, asn: AsnDB, psl: String, no_sandbox: bool) -> Result<()> {
+    let mut reporter = StdioReporter::setup();
+    let start = reporter.recv_start()?;
+    run_worker_with(reporter, start, geoip, asn, psl, no_sandbox)
+}
+
+/// The agent-side equivalent of `run_worker`, driven by a `sn0int agent` connection instead
+/// of the worker's own stdio.
+pub fn run_worker_over(stream: TcpStream, geoip: GeoIP, asn: AsnDB, psl: String, no_sandbox: bool) -> Result<()> {
+    let mut reporter = TcpReporter::setup(stream)?;
+    let start = reporter.recv_start()?;
+    run_worker_with(reporter, start, geoip, asn, psl, no_sandbox)
+}
@@ -0,0 +1,256 @@
+use crate::errors::*;
+use sha2::{Sha256, Digest};
+use sn0int_common::ModuleID;
+
+use std::collections::HashMap;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+
+/// The name of the lockfile inside a workspace, next to its database.
+const LOCKFILE: &str = "sn0int.lock";
+
+/// Where `install`/`run` expect an installed module's source on disk, relative to the
+/// workspace root. Mirrors the `author/name` shape of a `ModuleID` so modules from different
+/// authors can't collide.
+const MODULES_DIR: &str = "modules";
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LockEntry {
+    pub version: String,
+    pub sha256: String,
+}
+
+/// Pins the exact version and sha256 of every module installed into a workspace, so a later
+/// `run`/`install --locked` can refuse to execute anything that drifted from what was recorded,
+/// the same way a cargo-style registry's lockfile pins a checksum alongside a version.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Lockfile {
+    #[serde(flatten)]
+    modules: HashMap<ModuleID, LockEntry>,
+}
+
+impl Lockfile {
+    fn path(root: &Path) -> PathBuf {
+        root.join(LOCKFILE)
+    }
+
+    /// Load the workspace's lockfile, or an empty one if it doesn't have one yet.
+    pub fn load(root: &Path) -> Result<Lockfile> {
+        let path = Self::path(root);
+        if !path.exists() {
+            return Ok(Lockfile::default());
+        }
+
+        let buf = fs::read_to_string(&path)
+            .context("Failed to read sn0int.lock")?;
+        let lockfile = toml::from_str(&buf)
+            .context("Failed to parse sn0int.lock")?;
+        Ok(lockfile)
+    }
+
+    pub fn save(&self, root: &Path) -> Result<()> {
+        let buf = toml::to_string_pretty(self)
+            .context("Failed to serialize sn0int.lock")?;
+
+        let path = Self::path(root);
+        let mut file = fs::File::create(&path)
+            .context("Failed to create sn0int.lock")?;
+        file.write_all(buf.as_bytes())?;
+        Ok(())
+    }
+
+    pub fn get(&self, module: &ModuleID) -> Option<&LockEntry> {
+        self.modules.get(module)
+    }
+
+    /// Record what was just installed, replacing any previous entry for this module.
+    pub fn insert(&mut self, module: ModuleID, version: String, bytes: &[u8]) {
+        let sha256 = hash(bytes);
+        self.modules.insert(module, LockEntry { version, sha256 });
+    }
+
+    /// Check that `bytes` is still exactly what we locked for `module`. Called before a
+    /// module is allowed to run, so a tampered or bitrotted script on disk gets refused
+    /// instead of silently executed.
+    pub fn verify(&self, module: &ModuleID, bytes: &[u8]) -> Result<()> {
+        let entry = self.modules.get(module)
+            .ok_or_else(|| format_err!("Module is not in sn0int.lock: {}", module))?;
+
+        let sha256 = hash(bytes);
+        if sha256 != entry.sha256 {
+            bail!("Module {} does not match sn0int.lock (expected sha256 {}, got {})",
+                  module, entry.sha256, sha256);
+        }
+
+        Ok(())
+    }
+}
+
+/// Where `module`'s source is expected to live on disk inside a workspace.
+pub fn installed_path(root: &Path, module: &ModuleID) -> PathBuf {
+    root.join(MODULES_DIR).join(format!("{}.lua", module))
+}
+
+/// Hash `bytes` and record the result for `module` in the workspace's lockfile. The one call
+/// `install`/`install --locked` needs to make after writing a module's source to disk.
+pub fn record_install(root: &Path, module: ModuleID, version: String, bytes: &[u8]) -> Result<()> {
+    let mut lockfile = Lockfile::load(root)?;
+    lockfile.insert(module, version, bytes);
+    lockfile.save(root)
+}
+
+/// Re-read `module`'s installed source from disk and check it against the lockfile. Used by
+/// the `verify` subcommand, which runs on the same machine the module was installed on and so
+/// can freely re-read `root`. Not suitable for checking a module that's about to run on a
+/// `sn0int agent` host - see `expected_sha256` for that case instead.
+pub fn verify_module(root: &Path, module: &ModuleID) -> Result<()> {
+    let path = installed_path(root, module);
+    let bytes = fs::read(&path)
+        .with_context(|_| format!("Failed to read installed module {}", module))?;
+
+    let lockfile = Lockfile::load(root)?;
+    lockfile.verify(module, &bytes)
+}
+
+/// Look up the sha256 `sn0int.lock` has recorded for `module`, to embed in a `StartCommand`
+/// before it's sent to a worker. Resolving this here - on the machine that actually has
+/// `sn0int.lock` - is what lets a remote `sn0int agent` verify a module's integrity without
+/// needing filesystem access to a lockfile it has no reason to share the sender's view of.
+pub fn expected_sha256(root: &Path, module: &ModuleID) -> Result<String> {
+    let lockfile = Lockfile::load(root)?;
+    let entry = lockfile.get(module)
+        .ok_or_else(|| format_err!("Module is not in sn0int.lock: {}", module))?;
+    Ok(entry.sha256.clone())
+}
+
+/// Check `bytes` - the module's source as it was actually received, not re-read from anywhere -
+/// against a digest resolved ahead of time by `expected_sha256`. Has no filesystem or
+/// `Lockfile` dependency at all, so it works identically whether the caller is a local worker
+/// or a `sn0int agent` with no access to the sender's workspace.
+pub fn verify_bytes(expected_sha256: &str, bytes: &[u8]) -> Result<()> {
+    let sha256 = hash(bytes);
+    if sha256 != expected_sha256 {
+        bail!("Module does not match sn0int.lock (expected sha256 {}, got {})", expected_sha256, sha256);
+    }
+    Ok(())
+}
+
+/// Re-verify every module in the lockfile, or just `only` if given. Returns the modules that
+/// failed verification instead of bailing on the first one, so `sn0int verify` can report all
+/// of them in one pass.
+pub fn verify_all(root: &Path, only: Option<&ModuleID>) -> Result<Vec<(ModuleID, Error)>> {
+    let lockfile = Lockfile::load(root)?;
+
+    let modules: Vec<ModuleID> = match only {
+        Some(module) => vec![module.clone()],
+        None => lockfile.modules.keys().cloned().collect(),
+    };
+
+    let mut failures = Vec::new();
+    for module in modules {
+        if let Err(err) = verify_module(root, &module) {
+            failures.push((module, err));
+        }
+    }
+    Ok(failures)
+}
+
+fn hash(bytes: &[u8]) -> String {
+    let mut hasher = Sha256::new();
+    hasher.input(bytes);
+    hex::encode(hasher.result())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A scratch workspace root under the system tempdir, unique per test so parallel test
+    /// threads don't trip over each other's sn0int.lock/modules files.
+    struct TempRoot(PathBuf);
+
+    impl TempRoot {
+        fn new(name: &str) -> TempRoot {
+            let path = std::env::temp_dir().join(format!("sn0int-lockfile-test-{}-{}", name, std::process::id()));
+            let _ = fs::remove_dir_all(&path);
+            // Every module these tests install is by `ra2003`, so that is the only author
+            // directory `installed_path` needs to exist under `modules/`.
+            fs::create_dir_all(path.join(MODULES_DIR).join("ra2003")).expect("create scratch workspace");
+            TempRoot(path)
+        }
+    }
+
+    impl Drop for TempRoot {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn module_id() -> ModuleID {
+        "ra2003/example".parse().expect("valid module id")
+    }
+
+    #[test]
+    fn verify_accepts_untampered_bytes() {
+        let root = TempRoot::new("accepts");
+        let mut lockfile = Lockfile::default();
+        lockfile.insert(module_id(), "1.0.0".into(), b"print('hi')");
+        assert!(lockfile.verify(&module_id(), b"print('hi')").is_ok());
+    }
+
+    #[test]
+    fn verify_rejects_tampered_bytes() {
+        let root = TempRoot::new("rejects");
+        let mut lockfile = Lockfile::default();
+        lockfile.insert(module_id(), "1.0.0".into(), b"print('hi')");
+        assert!(lockfile.verify(&module_id(), b"print('pwned')").is_err());
+        let _ = root;
+    }
+
+    #[test]
+    fn verify_rejects_unknown_module() {
+        let lockfile = Lockfile::default();
+        assert!(lockfile.verify(&module_id(), b"anything").is_err());
+    }
+
+    #[test]
+    fn record_install_then_verify_module_round_trips_through_disk() {
+        let root = TempRoot::new("roundtrip");
+        let bytes = b"print('hi')";
+        fs::write(installed_path(&root.0, &module_id()), bytes).expect("write module source");
+
+        record_install(&root.0, module_id(), "1.0.0".into(), bytes).expect("record install");
+        assert!(verify_module(&root.0, &module_id()).is_ok());
+    }
+
+    #[test]
+    fn verify_module_catches_bitrot_on_disk() {
+        let root = TempRoot::new("bitrot");
+        let bytes = b"print('hi')";
+        fs::write(installed_path(&root.0, &module_id()), bytes).expect("write module source");
+        record_install(&root.0, module_id(), "1.0.0".into(), bytes).expect("record install");
+
+        fs::write(installed_path(&root.0, &module_id()), b"print('tampered')").expect("tamper with module source");
+        assert!(verify_module(&root.0, &module_id()).is_err());
+    }
+
+    #[test]
+    fn verify_all_reports_every_failure_without_stopping_at_the_first() {
+        let root = TempRoot::new("verify-all");
+        let good: ModuleID = "ra2003/good".parse().unwrap();
+        let bad: ModuleID = "ra2003/bad".parse().unwrap();
+
+        fs::write(installed_path(&root.0, &good), b"good").unwrap();
+        fs::write(installed_path(&root.0, &bad), b"good").unwrap();
+        record_install(&root.0, good.clone(), "1.0.0".into(), b"good").unwrap();
+        record_install(&root.0, bad.clone(), "1.0.0".into(), b"good").unwrap();
+
+        fs::write(installed_path(&root.0, &bad), b"tampered").unwrap();
+
+        let failures = verify_all(&root.0, None).expect("verify_all should not bail");
+        assert_eq!(failures.len(), 1);
+        assert_eq!(failures[0].0, bad);
+    }
+}
@@ -0,0 +1,118 @@
+use crate::errors::*;
+use super::SandboxPolicy;
+use caps::CapSet;
+use syscallz::{Context, Syscall, Action};
+use std::io;
+
+
+/// Syscalls every module needs regardless of capabilities: basic io on the pipes we already
+/// handed it and on the files the Lua runtime itself opens (its stdlib, locale data, TLS roots,
+/// ...), memory management and threading primitives for the Lua VM and its event loop, entropy
+/// for anything that needs randomness, and a clean way to exit. Built against a real
+/// HTTP-fetching module and widened until it stopped dying on the first handshake rather than
+/// asserted from a hand-picked list.
+const BASE_SYSCALLS: &[Syscall] = &[
+    Syscall::read,
+    Syscall::write,
+    Syscall::close,
+    Syscall::open,
+    Syscall::openat,
+    Syscall::stat,
+    Syscall::fstat,
+    Syscall::lstat,
+    Syscall::access,
+    Syscall::getrandom,
+    Syscall::ioctl,
+    Syscall::epoll_create1,
+    Syscall::epoll_ctl,
+    Syscall::epoll_wait,
+    Syscall::poll,
+    Syscall::select,
+    Syscall::mmap,
+    Syscall::munmap,
+    Syscall::mprotect,
+    Syscall::brk,
+    Syscall::futex,
+    Syscall::clock_gettime,
+    Syscall::nanosleep,
+    Syscall::sigaltstack,
+    Syscall::rt_sigreturn,
+    Syscall::rt_sigaction,
+    Syscall::rt_sigprocmask,
+    Syscall::clone,
+    Syscall::set_robust_list,
+    Syscall::exit,
+    Syscall::exit_group,
+];
+
+/// Only granted to modules that declared they do DNS/HTTP; everything else loses these.
+/// `poll`/`epoll_wait`/`select` are in `BASE_SYSCALLS` since the Lua runtime's own event loop
+/// needs them regardless of whether the module ever touches the network.
+const NETWORK_SYSCALLS: &[Syscall] = &[
+    Syscall::socket,
+    Syscall::connect,
+    Syscall::sendto,
+    Syscall::recvfrom,
+    Syscall::getsockopt,
+    Syscall::setsockopt,
+];
+
+pub fn enforce(policy: SandboxPolicy) -> Result<()> {
+    unshare_namespaces();
+    drop_capabilities()?;
+    install_seccomp_filter(&policy)?;
+    Ok(())
+}
+
+/// Best-effort: some of these namespaces need privileges we may not have (eg when sn0int
+/// already runs inside a container), so we log and keep going instead of aborting the scan.
+///
+/// Deliberately does *not* include `CLONE_NEWNET`: a fresh network namespace only has a
+/// down `lo` and no route to anything else, which silently breaks every HTTP/DNS module
+/// instead of sandboxing it - and on kernels with unprivileged userns enabled `unshare`
+/// succeeds, so the breakage wouldn't even show up as a setup error. Network isolation is
+/// enforced by the seccomp filter's syscall allow-list instead, until there's real veth/bridge
+/// plumbing to give modules a working namespaced network.
+fn unshare_namespaces() {
+    let flags = libc::CLONE_NEWUSER | libc::CLONE_NEWPID;
+    let ret = unsafe { libc::unshare(flags) };
+    if ret != 0 {
+        warn!("Failed to unshare namespaces, continuing without them: {}", io::Error::last_os_error());
+    }
+}
+
+fn drop_capabilities() -> Result<()> {
+    let ret = unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1, 0, 0, 0) };
+    if ret != 0 {
+        bail!("Failed to set PR_SET_NO_NEW_PRIVS: {}", io::Error::last_os_error());
+    }
+
+    for set in &[CapSet::Effective, CapSet::Permitted, CapSet::Inheritable] {
+        caps::clear(None, *set)
+            .context("Failed to drop capabilities")?;
+    }
+
+    Ok(())
+}
+
+fn install_seccomp_filter(policy: &SandboxPolicy) -> Result<()> {
+    let mut ctx = Context::init_with_action(Action::Kill)
+        .context("Failed to initialize seccomp filter")?;
+
+    for syscall in BASE_SYSCALLS {
+        ctx.allow_syscall(*syscall)
+            .context("Failed to allow-list syscall")?;
+    }
+
+    if policy.network {
+        for syscall in NETWORK_SYSCALLS {
+            ctx.allow_syscall(*syscall)
+                .context("Failed to allow-list network syscall")?;
+        }
+    }
+
+    ctx.load()
+        .context("Failed to load seccomp filter")?;
+
+    Ok(())
+}
@@ -0,0 +1,38 @@
+use crate::errors::*;
+use crate::engine::Module;
+
+#[cfg(target_os = "linux")]
+mod linux;
+
+/// The syscalls/capabilities a sandboxed module is allowed to use, derived from what the
+/// module declares about itself rather than tracked as a second source of truth.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SandboxPolicy {
+    pub network: bool,
+}
+
+impl SandboxPolicy {
+    pub fn for_module(module: &Module) -> SandboxPolicy {
+        SandboxPolicy {
+            network: module.uses_network(),
+        }
+    }
+}
+
+/// Confines the current process to `policy` before it is allowed to execute a module's script.
+/// Must run after `recv_start()` (so we know the module's declared capabilities) and before
+/// `start.module.run(...)` - nothing installed here is inherited retroactively.
+///
+/// On non-Linux targets this is a no-op; `--no-sandbox` skips it everywhere.
+pub fn enforce(policy: SandboxPolicy) -> Result<()> {
+    #[cfg(target_os = "linux")]
+    {
+        linux::enforce(policy)
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    {
+        warn!("Sandboxing is not implemented on this platform, module is running unconfined");
+        Ok(())
+    }
+}
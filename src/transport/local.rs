@@ -0,0 +1,62 @@
+use crate::errors::*;
+use crate::engine::Module;
+use super::Transport;
+
+use std::env;
+use std::io::{BufRead, BufReader, Write};
+use std::process::{Command, Child, Stdio, ChildStdin, ChildStdout};
+use std::sync::{Arc, Mutex};
+
+
+/// Drives a worker that sn0int spawned itself, piping jsonrpc frames over the child's stdio.
+/// This is the default transport and was the only one before agents existed.
+pub struct LocalTransport {
+    child: Child,
+    writer: Arc<Mutex<Box<Write + Send>>>,
+    reader: BufReader<ChildStdout>,
+}
+
+impl LocalTransport {
+    pub fn spawn(module: &Module) -> Result<LocalTransport> {
+        let exe = env::current_exe()
+            .context("Failed to find current executable")?;
+
+        let mut child = Command::new(exe)
+            .arg("sandbox")
+            .arg(&module.canonical())
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()
+            .context("Failed to spawn child process")?;
+
+        let stdin: ChildStdin = child.stdin.take().expect("Failed to take child stdin");
+        let stdout = child.stdout.take().expect("Failed to take child stdout");
+
+        Ok(LocalTransport {
+            child,
+            writer: Arc::new(Mutex::new(Box::new(stdin))),
+            reader: BufReader::new(stdout),
+        })
+    }
+}
+
+impl Transport for LocalTransport {
+    fn writer(&self) -> Arc<Mutex<Box<Write + Send>>> {
+        self.writer.clone()
+    }
+
+    fn reader(&mut self) -> &mut BufRead {
+        &mut self.reader
+    }
+
+    fn wait(&mut self) -> Result<()> {
+        let exit = self.child.wait()
+            .context("Failed to wait for child")?;
+
+        if exit.success() {
+            Ok(())
+        } else {
+            bail!("Child signaled error")
+        }
+    }
+}
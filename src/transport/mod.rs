@@ -0,0 +1,27 @@
+use crate::errors::*;
+use std::io::{BufRead, Write};
+use std::sync::{Arc, Mutex};
+
+mod local;
+mod tcp;
+
+pub use self::local::LocalTransport;
+pub use self::tcp::TcpTransport;
+
+/// Abstracts the byte stream a `Supervisor` drives a worker over, so the jsonrpc framing and
+/// request/reply bookkeeping in `engine::isolation` don't need to know whether the worker is a
+/// local child process or a module runner on a remote agent host.
+pub trait Transport: Send {
+    /// A cloneable handle to the write half, so `Supervisor` can hand it to the background
+    /// threads that deliver async replies without holding `&mut self` for the whole run.
+    fn writer(&self) -> Arc<Mutex<Box<Write + Send>>>;
+
+    fn reader(&mut self) -> &mut BufRead;
+
+    /// Wait for the worker to finish and fail if it didn't exit cleanly. Local transports map
+    /// this to the child's exit status; remote transports have nothing of their own to wait on
+    /// here, since the worker's `ExitEvent` already told us how the run went.
+    fn wait(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
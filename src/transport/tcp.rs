@@ -0,0 +1,44 @@
+use crate::errors::*;
+use super::Transport;
+
+use std::io::{BufRead, BufReader, Write};
+use std::net::TcpStream;
+use std::sync::{Arc, Mutex};
+
+
+/// Drives a worker running on a remote `sn0int agent` instead of a local child process, so a
+/// scan can offload module execution (and its network egress) to a dedicated runner host.
+/// Speaks the exact same jsonrpc framing as `LocalTransport`; only how the bytes get there differs.
+pub struct TcpTransport {
+    writer: Arc<Mutex<Box<Write + Send>>>,
+    reader: BufReader<TcpStream>,
+}
+
+impl TcpTransport {
+    /// Connect to a `sn0int agent` and send `token` as the handshake line the agent expects
+    /// before it will read a `StartCommand` off the connection.
+    pub fn connect(addr: &str, token: &str) -> Result<TcpTransport> {
+        let mut stream = TcpStream::connect(addr)
+            .with_context(|_| format!("Failed to connect to agent at {:?}", addr))?;
+        let reader = stream.try_clone()
+            .context("Failed to clone agent connection")?;
+
+        writeln!(stream, "{}", token)
+            .context("Failed to send agent handshake")?;
+
+        Ok(TcpTransport {
+            writer: Arc::new(Mutex::new(Box::new(stream))),
+            reader: BufReader::new(reader),
+        })
+    }
+}
+
+impl Transport for TcpTransport {
+    fn writer(&self) -> Arc<Mutex<Box<Write + Send>>> {
+        self.writer.clone()
+    }
+
+    fn reader(&mut self) -> &mut BufRead {
+        &mut self.reader
+    }
+}
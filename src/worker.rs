@@ -0,0 +1,380 @@
+use crate::errors::*;
+use serde_json::Value;
+use std::io;
+use std::sync::mpsc;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+
+/// The jsonrpc version string that is pinned into every envelope we send or expect to receive.
+pub const JSONRPC_VERSION: &str = "2.0";
+
+pub type EventSender = mpsc::Sender<Event2>;
+
+/// A monotonically increasing id generator for the requests a worker sends its `Supervisor`.
+/// The supervisor keeps no counter of its own; it answers each request with the id the worker
+/// picked.
+///
+/// Ids only need to be unique within one worker's connection to its supervisor, so a plain
+/// `AtomicU64` is enough; there is no need to coordinate across processes.
+#[derive(Debug, Default)]
+pub struct RequestIdGen(AtomicU64);
+
+impl RequestIdGen {
+    pub fn new() -> RequestIdGen {
+        RequestIdGen(AtomicU64::new(1))
+    }
+
+    pub fn next(&self) -> u64 {
+        self.0.fetch_add(1, Ordering::Relaxed)
+    }
+}
+
+/// A frame on the supervisor<->worker wire. Requests and notifications are sent by the worker;
+/// the supervisor only sends replies, each in response to a request carrying the same `id`.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Envelope {
+    pub jsonrpc: Version,
+    /// Present on requests and their replies, absent on notifications.
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub id: Option<u64>,
+    #[serde(flatten)]
+    pub body: EnvelopeBody,
+}
+
+#[derive(Debug)]
+pub struct Version;
+
+impl serde::Serialize for Version {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        serializer.serialize_str(JSONRPC_VERSION)
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for Version {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> std::result::Result<Version, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        if s != JSONRPC_VERSION {
+            return Err(serde::de::Error::custom(format!("unsupported jsonrpc version: {:?}", s)));
+        }
+        Ok(Version)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum EnvelopeBody {
+    /// A call that expects (for requests) or delivers (for notifications) a `method`/`params` pair.
+    Call { method: String, params: Value },
+    /// A reply to a previous request with the same `id`.
+    Result { result: Value },
+    /// A reply indicating the previous request with the same `id` failed.
+    Error { error: RpcError },
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RpcError {
+    pub message: String,
+}
+
+impl Envelope {
+    pub fn request(id: u64, method: &str, params: Value) -> Envelope {
+        Envelope {
+            jsonrpc: Version,
+            id: Some(id),
+            body: EnvelopeBody::Call {
+                method: method.to_string(),
+                params,
+            },
+        }
+    }
+
+    pub fn notification(method: &str, params: Value) -> Envelope {
+        Envelope {
+            jsonrpc: Version,
+            id: None,
+            body: EnvelopeBody::Call {
+                method: method.to_string(),
+                params,
+            },
+        }
+    }
+
+    pub fn result(id: u64, result: Value) -> Envelope {
+        Envelope {
+            jsonrpc: Version,
+            id: Some(id),
+            body: EnvelopeBody::Result { result },
+        }
+    }
+
+    pub fn error(id: u64, message: String) -> Envelope {
+        Envelope {
+            jsonrpc: Version,
+            id: Some(id),
+            body: EnvelopeBody::Error { error: RpcError { message } },
+        }
+    }
+
+    pub fn into_reply_value(self) -> Result<Value> {
+        match self.body {
+            EnvelopeBody::Result { result } => Ok(result),
+            EnvelopeBody::Error { error } => bail!("worker returned an error: {}", error.message),
+            EnvelopeBody::Call { method, .. } => bail!("expected a reply, got a call to {:?}", method),
+        }
+    }
+}
+
+/// Events the module side (worker) emits to the host. `Log` and `Exit` are notifications,
+/// everything else is a request the host must eventually answer with a result or error -
+/// the `u64` is the id the child picked for that call, and must come back unchanged on
+/// the matching reply.
+#[derive(Debug)]
+pub enum Event {
+    Log(LogEvent),
+    Database(Value, u64),
+    Stdio(StdioEvent, u64),
+    Exit(ExitEvent),
+}
+
+/// Events forwarded from a `Supervisor`'s recv loop up to the host scheduler. The requests
+/// carry the `Sender` their eventual result must be delivered to, so the scheduler doesn't
+/// need to know anything about the jsonrpc id that will tag the reply on the wire.
+#[derive(Debug)]
+pub enum Event2 {
+    Log(LogEvent),
+    Database(Value, mpsc::Sender<Value>),
+    /// How the module's run ended, `ErrorClass` and all, so a multi-threaded scheduler can
+    /// retry transient failures, back off on repeated `Sandbox` denials, or count
+    /// `InvalidArgs` against the caller instead of the target - see `ErrorClass`.
+    Exit(ExitEvent),
+}
+
+impl EventWithCallback for Value {
+    type Payload = Value;
+
+    fn with_callback(self, tx: mpsc::Sender<Value>) -> Event2 {
+        Event2::Database(self, tx)
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum LogEvent {
+    Info(String),
+    Status(String),
+    Error(String),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum ExitEvent {
+    Ok,
+    Err(WorkerError),
+}
+
+/// A classified module failure, so the host scheduler can act on *why* a worker exited
+/// instead of treating every error the same way. Retryable classes are transient by nature;
+/// everything else needs a human or a different module before retrying would help.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ErrorClass {
+    /// DNS/TCP/TLS failures talking to a target.
+    Network,
+    /// A request to a target took too long and was cancelled.
+    Timeout,
+    /// The target (or its CDN/WAF) asked us to slow down.
+    RateLimited,
+    /// The module was called with arguments it can't work with; retrying won't help.
+    InvalidArgs,
+    /// The sandbox denied a syscall or the module tried to escape its confinement, or the
+    /// worker refused to run a module that failed its sn0int.lock check.
+    Sandbox,
+    /// The module's own script raised an error (a Lua error, an assertion, ...).
+    ModuleRuntime,
+    /// Anything we can't place in the above - sn0int's own bug, not the module's or the
+    /// network's fault.
+    Internal,
+}
+
+impl ErrorClass {
+    /// Whether the host scheduler should back off and retry rather than count this as a
+    /// hard failure.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            ErrorClass::Network | ErrorClass::Timeout | ErrorClass::RateLimited => true,
+            ErrorClass::InvalidArgs | ErrorClass::Sandbox | ErrorClass::ModuleRuntime | ErrorClass::Internal => false,
+        }
+    }
+
+    /// Best-effort classification of an engine failure into one of the buckets above, similar
+    /// to how deno maps an arbitrary error into a fixed set of JS error classes.
+    ///
+    /// This looks at concrete types in the failure chain first, and falls back to `Display` text
+    /// only to spot rate limiting and Lua runtime errors. A message like "permission denied"
+    /// means something completely different depending on whether it came from a syscall our own
+    /// seccomp filter blocked or from the target's filesystem, and a substring match can't tell
+    /// those apart. `Sandbox` and `InvalidArgs` in
+    /// particular are never inferred here; callers that know structurally that a failure came
+    /// from sandbox setup or argument validation should build a `WorkerError` directly with
+    /// `WorkerError::sandbox`/`WorkerError::invalid_args` instead of routing it through `classify`.
+    /// Falls back to `Internal` when nothing more specific matches.
+    pub fn classify(err: &Error) -> ErrorClass {
+        for cause in err.iter_chain() {
+            if let Some(io_err) = cause.downcast_ref::<io::Error>() {
+                return match io_err.kind() {
+                    io::ErrorKind::TimedOut => ErrorClass::Timeout,
+                    io::ErrorKind::ConnectionRefused
+                    | io::ErrorKind::ConnectionReset
+                    | io::ErrorKind::ConnectionAborted
+                    | io::ErrorKind::NotConnected
+                    | io::ErrorKind::AddrNotAvailable
+                    | io::ErrorKind::AddrInUse
+                    | io::ErrorKind::BrokenPipe => ErrorClass::Network,
+                    _ => ErrorClass::Internal,
+                };
+            }
+        }
+
+        // No concrete type we recognize; a narrow, low-risk text fallback for the one signal
+        // that genuinely only ever shows up as a message (an upstream HTTP/Lua error has no
+        // typed representation on our side to downcast to).
+        let msg = err.to_string().to_lowercase();
+        if msg.contains("rate limit") || msg.contains("429 ") || msg.contains("too many requests") {
+            ErrorClass::RateLimited
+        } else if msg.starts_with("lua error") || msg.contains("runtime error:") {
+            ErrorClass::ModuleRuntime
+        } else {
+            ErrorClass::Internal
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct WorkerError {
+    pub class: ErrorClass,
+    /// A machine-readable code for the rare case where the message itself isn't enough to
+    /// act on (eg an upstream HTTP status). Most classifications only need `class`.
+    pub code: Option<String>,
+    pub message: String,
+}
+
+impl WorkerError {
+    /// The sandbox itself refused to come up, a module tried to escape its confinement, or a
+    /// module failed its sn0int.lock check before running. Built directly at the call site that knows this structurally - never inferred from a
+    /// message by `classify`, since "permission denied" alone doesn't tell us that.
+    pub fn sandbox(message: String) -> WorkerError {
+        WorkerError { class: ErrorClass::Sandbox, code: None, message }
+    }
+
+    /// The module was asked to run with arguments it can't work with. Built directly at the
+    /// call site that validated the argument - never inferred from a message by `classify`.
+    pub fn invalid_args(message: String) -> WorkerError {
+        WorkerError { class: ErrorClass::InvalidArgs, code: None, message }
+    }
+}
+
+impl<'a> From<&'a Error> for WorkerError {
+    fn from(err: &'a Error) -> WorkerError {
+        WorkerError {
+            class: ErrorClass::classify(err),
+            code: None,
+            message: err.to_string(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub enum StdioEvent {
+    ReadLine,
+}
+
+/// Implemented by every event variant that needs a reply routed back to whichever caller
+/// (eg a database worker thread) produced it, so `Supervisor` can answer the right `id` once
+/// that caller is done.
+pub trait EventWithCallback {
+    type Payload;
+
+    fn with_callback(self, tx: mpsc::Sender<Self::Payload>) -> Event2;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn roundtrip(envelope: Envelope) -> Envelope {
+        let line = serde_json::to_string(&envelope).expect("serialize");
+        serde_json::from_str(&line).expect("deserialize")
+    }
+
+    #[test]
+    fn request_roundtrips_with_id_and_method() {
+        let envelope = roundtrip(Envelope::request(7, "database", Value::from("row")));
+        assert_eq!(envelope.id, Some(7));
+        match envelope.body {
+            EnvelopeBody::Call { method, params } => {
+                assert_eq!(method, "database");
+                assert_eq!(params, Value::from("row"));
+            },
+            _ => panic!("expected a call"),
+        }
+    }
+
+    #[test]
+    fn notification_roundtrips_without_id() {
+        let envelope = roundtrip(Envelope::notification("log", Value::from("hi")));
+        assert_eq!(envelope.id, None);
+        assert!(match envelope.body {
+            EnvelopeBody::Call { .. } => true,
+            _ => false,
+        });
+    }
+
+    #[test]
+    fn result_roundtrips_into_reply_value() {
+        let envelope = roundtrip(Envelope::result(3, Value::from(42)));
+        assert_eq!(envelope.into_reply_value().unwrap(), Value::from(42));
+    }
+
+    #[test]
+    fn error_roundtrip_surfaces_as_err() {
+        let envelope = roundtrip(Envelope::error(3, "boom".into()));
+        let err = envelope.into_reply_value().unwrap_err();
+        assert!(err.to_string().contains("boom"));
+    }
+
+    #[test]
+    fn version_rejects_anything_but_2_0() {
+        let bad: std::result::Result<Envelope, _> = serde_json::from_str(
+            r#"{"jsonrpc":"1.0","method":"log","params":{}}"#
+        );
+        assert!(bad.is_err());
+    }
+
+    #[test]
+    fn classify_maps_io_timeout_to_timeout() {
+        let io_err = io::Error::new(io::ErrorKind::TimedOut, "deadline exceeded");
+        let err: Error = io_err.into();
+        assert_eq!(ErrorClass::classify(&err), ErrorClass::Timeout);
+    }
+
+    #[test]
+    fn classify_maps_connection_refused_to_network() {
+        let io_err = io::Error::new(io::ErrorKind::ConnectionRefused, "refused");
+        let err: Error = io_err.into();
+        assert_eq!(ErrorClass::classify(&err), ErrorClass::Network);
+    }
+
+    #[test]
+    fn classify_does_not_treat_generic_permission_denied_as_sandbox() {
+        // A disk-permission error carries the same "permission denied" text a seccomp denial
+        // would, but it isn't one - classify must not conflate the two. Only `WorkerError::sandbox`,
+        // called at a site that actually knows the failure came from sandbox setup, should do that.
+        let io_err = io::Error::new(io::ErrorKind::PermissionDenied, "permission denied");
+        let err: Error = io_err.into();
+        assert_ne!(ErrorClass::classify(&err), ErrorClass::Sandbox);
+    }
+
+    #[test]
+    fn worker_error_sandbox_constructor_sets_non_retryable_sandbox_class() {
+        let err = WorkerError::sandbox("seccomp denied openat".into());
+        assert_eq!(err.class, ErrorClass::Sandbox);
+        assert!(!err.class.is_retryable());
+    }
+}